@@ -0,0 +1,249 @@
+//! Proc-macro companion to `columnar`: `#[derive(Columnar)]` generates a
+//! struct-of-arrays buffer for product types (structs) and a tagged union
+//! of sub-buffers for sum types (enums), so callers no longer have to
+//! hand-write a `ColumnarBuf` impl for every type they want to store.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+#[proc_macro_derive(Columnar)]
+pub fn derive_columnar(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let expanded = match &input.data {
+        Data::Struct(data) => derive_struct(&input, data),
+        Data::Enum(data) => derive_enum(&input, data),
+        Data::Union(_) => syn::Error::new_spanned(&input, "Columnar cannot be derived for unions")
+            .to_compile_error(),
+    };
+    expanded.into()
+}
+
+fn derive_struct(input: &DeriveInput, data: &syn::DataStruct) -> TokenStream2 {
+    let name = &input.ident;
+    let buf_name = format_ident!("{}Buf", name);
+    let ref_name = format_ident!("{}Ref", name);
+
+    let fields = match &data.fields {
+        Fields::Named(fields) => fields.named.iter().collect::<Vec<_>>(),
+        Fields::Unnamed(_) | Fields::Unit => {
+            return syn::Error::new_spanned(
+                &input.ident,
+                "Columnar can only be derived for structs with named fields",
+            )
+            .to_compile_error();
+        }
+    };
+    if fields.is_empty() {
+        return syn::Error::new_spanned(&input.ident, "Columnar requires at least one field")
+            .to_compile_error();
+    }
+
+    let field_idents = fields.iter().map(|f| f.ident.as_ref().unwrap()).collect::<Vec<_>>();
+    let field_types = fields.iter().map(|f| &f.ty).collect::<Vec<_>>();
+    let first_field = field_idents[0];
+
+    quote! {
+        pub struct #buf_name {
+            #( #field_idents: <#field_types as columnar::Columnar>::Buf, )*
+        }
+
+        pub struct #ref_name<'a> {
+            #( pub #field_idents: <<#field_types as columnar::Columnar>::Buf as columnar::ColumnarBuf<#field_types>>::ReadItem<'a>, )*
+        }
+
+        impl columnar::borrow::Borrow for #name {
+            type Borrowed = #name;
+            fn borrow(&self) -> &Self::Borrowed {
+                self
+            }
+        }
+
+        impl columnar::Columnar for #name {
+            type Buf = #buf_name;
+
+            fn from_read_item(item: #ref_name<'_>) -> Self {
+                #name {
+                    #( #field_idents: <#field_types as columnar::Columnar>::from_read_item(item.#field_idents), )*
+                }
+            }
+        }
+
+        impl columnar::ColumnarBuf<#name> for #buf_name {
+            type ReadItem<'a> = #ref_name<'a>;
+
+            fn copy(&mut self, c: &#name) {
+                #( self.#field_idents.copy(columnar::borrow::Borrow::borrow(&c.#field_idents)); )*
+            }
+
+            fn try_copy(&mut self, c: &#name) -> Result<(), columnar::TryReserveError> {
+                #( self.#field_idents.try_copy(columnar::borrow::Borrow::borrow(&c.#field_idents))?; )*
+                Ok(())
+            }
+
+            fn idx(&self, i: usize) -> Self::ReadItem<'_> {
+                #ref_name {
+                    #( #field_idents: <<#field_types as columnar::Columnar>::Buf as columnar::ColumnarBuf<#field_types>>::idx(&self.#field_idents, i), )*
+                }
+            }
+
+            fn len(&self) -> usize {
+                self.#first_field.len()
+            }
+
+            fn with_capacity(s: usize) -> Self {
+                #buf_name {
+                    #( #field_idents: <<#field_types as columnar::Columnar>::Buf as columnar::ColumnarBuf<#field_types>>::with_capacity(s), )*
+                }
+            }
+        }
+    }
+}
+
+fn derive_enum(input: &DeriveInput, data: &syn::DataEnum) -> TokenStream2 {
+    let name = &input.ident;
+    let buf_name = format_ident!("{}Buf", name);
+    let ref_name = format_ident!("{}Ref", name);
+
+    let mut variant_idents = Vec::new();
+    let mut variant_bufs = Vec::new();
+    let mut variant_payload_tys = Vec::new();
+    let mut variant_patterns = Vec::new();
+    let mut variant_copy_exprs = Vec::new();
+    let mut variant_try_copy_exprs = Vec::new();
+    let mut variant_reconstruct_exprs = Vec::new();
+
+    for variant in &data.variants {
+        let v_ident = &variant.ident;
+        let buf_field = format_ident!("{}_buf", to_snake_case(&v_ident.to_string()));
+        match &variant.fields {
+            Fields::Unit => {
+                variant_patterns.push(quote! { #name::#v_ident });
+                variant_copy_exprs.push(quote! { self.#buf_field.copy(&()) });
+                variant_try_copy_exprs.push(quote! { self.#buf_field.try_copy(&()) });
+                variant_payload_tys.push(quote! { () });
+                variant_reconstruct_exprs.push(quote! { #name::#v_ident });
+            }
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                let ty = &fields.unnamed.first().unwrap().ty;
+                variant_patterns.push(quote! { #name::#v_ident(ref inner) });
+                variant_copy_exprs
+                    .push(quote! { self.#buf_field.copy(columnar::borrow::Borrow::borrow(inner)) });
+                variant_try_copy_exprs.push(
+                    quote! { self.#buf_field.try_copy(columnar::borrow::Borrow::borrow(inner)) },
+                );
+                variant_payload_tys.push(quote! { #ty });
+                variant_reconstruct_exprs.push(
+                    quote! { #name::#v_ident(<#ty as columnar::Columnar>::from_read_item(inner)) },
+                );
+            }
+            _ => {
+                return syn::Error::new_spanned(
+                    variant,
+                    "Columnar only supports unit variants or single-field tuple variants",
+                )
+                .to_compile_error();
+            }
+        }
+        variant_idents.push(v_ident.clone());
+        variant_bufs.push(buf_field.clone());
+    }
+
+    let tags = (0u8..variant_idents.len() as u8).collect::<Vec<_>>();
+
+    quote! {
+        pub enum #ref_name<'a> {
+            #( #variant_idents(<<#variant_payload_tys as columnar::Columnar>::Buf as columnar::ColumnarBuf<#variant_payload_tys>>::ReadItem<'a>), )*
+        }
+
+        pub struct #buf_name {
+            tag: columnar::region::Region<u8>,
+            variant_idx: columnar::region::Region<usize>,
+            #( #variant_bufs: <#variant_payload_tys as columnar::Columnar>::Buf, )*
+        }
+
+        impl columnar::borrow::Borrow for #name {
+            type Borrowed = #name;
+            fn borrow(&self) -> &Self::Borrowed {
+                self
+            }
+        }
+
+        impl columnar::Columnar for #name {
+            type Buf = #buf_name;
+
+            fn from_read_item(item: #ref_name<'_>) -> Self {
+                match item {
+                    #( #ref_name::#variant_idents(inner) => #variant_reconstruct_exprs, )*
+                }
+            }
+        }
+
+        impl columnar::ColumnarBuf<#name> for #buf_name {
+            type ReadItem<'a> = #ref_name<'a>;
+
+            fn copy(&mut self, c: &#name) {
+                match c {
+                    #( #variant_patterns => {
+                        self.tag.copy(&#tags);
+                        self.variant_idx.copy(&self.#variant_bufs.len());
+                        #variant_copy_exprs;
+                    } )*
+                }
+            }
+
+            fn try_copy(&mut self, c: &#name) -> Result<(), columnar::TryReserveError> {
+                match c {
+                    #( #variant_patterns => {
+                        // Reserve the tag and variant-index slots *before*
+                        // writing the payload, so that a payload failure
+                        // leaves the buffer unchanged instead of stranding
+                        // a tag/index pair with no matching payload.
+                        self.tag.try_reserve(1)?;
+                        self.variant_idx.try_reserve(1)?;
+                        let vi = self.#variant_bufs.len();
+                        #variant_try_copy_exprs?;
+                        self.tag.copy(&#tags);
+                        self.variant_idx.copy(&vi);
+                    } )*
+                }
+                Ok(())
+            }
+
+            fn idx(&self, i: usize) -> Self::ReadItem<'_> {
+                let tag = *columnar::region::Region::idx(&self.tag, i);
+                let vi = *columnar::region::Region::idx(&self.variant_idx, i);
+                match tag {
+                    #( #tags => #ref_name::#variant_idents(
+                        <<#variant_payload_tys as columnar::Columnar>::Buf as columnar::ColumnarBuf<#variant_payload_tys>>::idx(&self.#variant_bufs, vi),
+                    ), )*
+                    _ => unreachable!("invalid Columnar enum tag"),
+                }
+            }
+
+            fn len(&self) -> usize {
+                columnar::region::Region::len(&self.tag)
+            }
+
+            fn with_capacity(s: usize) -> Self {
+                #buf_name {
+                    tag: columnar::region::Region::with_limit_and_capacity(1_000_000, s),
+                    variant_idx: columnar::region::Region::with_limit_and_capacity(1_000_000, s),
+                    #( #variant_bufs: <<#variant_payload_tys as columnar::Columnar>::Buf as columnar::ColumnarBuf<#variant_payload_tys>>::with_capacity(s), )*
+                }
+            }
+        }
+    }
+}
+
+fn to_snake_case(s: &str) -> String {
+    let mut out = String::new();
+    for (i, ch) in s.char_indices() {
+        if ch.is_uppercase() && i != 0 {
+            out.push('_');
+        }
+        out.extend(ch.to_lowercase());
+    }
+    out
+}
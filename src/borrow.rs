@@ -18,6 +18,13 @@ impl Borrow for String {
     }
 }
 
+impl Borrow for () {
+    type Borrowed = ();
+    fn borrow(&self) -> &Self::Borrowed {
+        self
+    }
+}
+
 impl<T> Borrow for Vec<T> {
     type Borrowed = [T];
 
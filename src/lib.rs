@@ -1,14 +1,30 @@
+// `Region` can optionally be generalized over `std::alloc::Allocator` via the
+// `allocator_api` Cargo feature, which is still nightly-only. Gated behind
+// `cfg_attr` so that building without the feature stays on stable Rust.
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+
+// Lets `#[derive(Columnar)]` refer to this crate as `columnar::...` even from
+// within the crate's own tests, where there is no external `columnar` in scope.
+extern crate self as columnar;
+
 pub mod borrow;
 pub mod region;
 
+pub use columnar_derive::Columnar;
+pub use std::collections::TryReserveError;
+
 use crate::borrow::Borrow;
 use crate::region::Region;
 
 pub trait Columnar: Borrow + Sized {
     type Buf: ColumnarBuf<Self>;
+
+    /// Reconstructs an owned `Self` from a borrowed read item, the inverse
+    /// of storing a value with [`ColumnarBuf::copy`].
+    fn from_read_item(item: <Self::Buf as ColumnarBuf<Self>>::ReadItem<'_>) -> Self;
 }
 
-pub trait ColumnarBuf<C: Columnar> {
+pub trait ColumnarBuf<C: Columnar<Buf = Self>> {
     type ReadItem<'a>
     where
         Self: 'a;
@@ -16,10 +32,47 @@ pub trait ColumnarBuf<C: Columnar> {
     fn idx(&self, i: usize) -> Self::ReadItem<'_>;
     fn len(&self) -> usize;
     fn with_capacity(s: usize) -> Self;
+
+    /// Returns `true` if the buffer holds no items.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Like [`ColumnarBuf::copy`], but surfaces allocation failure as a
+    /// [`TryReserveError`] instead of aborting the process.
+    ///
+    /// The default implementation falls back to `copy`, so only buffers
+    /// that manage their own allocation need to override it.
+    fn try_copy(&mut self, b: &C::Borrowed) -> Result<(), TryReserveError> {
+        self.copy(b);
+        Ok(())
+    }
+
+    /// Iterates over the borrowed read items stored in this buffer.
+    fn iter(&self) -> impl Iterator<Item = Self::ReadItem<'_>>
+    where
+        Self: Sized,
+    {
+        (0..self.len()).map(move |i| self.idx(i))
+    }
+
+    /// Consumes the buffer, reconstructing and iterating over the owned
+    /// values it held. This is the inverse of repeatedly calling `copy`.
+    fn into_values(self) -> impl Iterator<Item = C>
+    where
+        Self: Sized,
+    {
+        let len = self.len();
+        (0..len).map(move |i| C::from_read_item(self.idx(i)))
+    }
 }
 
 impl Columnar for u64 {
     type Buf = Region<u64>;
+
+    fn from_read_item(item: u64) -> Self {
+        item
+    }
 }
 
 impl ColumnarBuf<u64> for Region<u64> {
@@ -29,6 +82,42 @@ impl ColumnarBuf<u64> for Region<u64> {
         self.copy(c)
     }
 
+    fn try_copy(&mut self, c: &u64) -> Result<(), TryReserveError> {
+        Region::try_copy(self, c)
+    }
+
+    fn idx(&self, i: usize) -> Self::ReadItem<'_> {
+        *Region::idx(self, i)
+    }
+
+    fn len(&self) -> usize {
+        Region::len(self)
+    }
+
+    fn with_capacity(s: usize) -> Self {
+        Region::with_limit_and_capacity(1_000_000, s)
+    }
+}
+
+impl Columnar for () {
+    type Buf = Region<()>;
+
+    fn from_read_item(item: ()) -> Self {
+        item
+    }
+}
+
+impl ColumnarBuf<()> for Region<()> {
+    type ReadItem<'a> = ();
+
+    fn copy(&mut self, c: &()) {
+        self.copy(c)
+    }
+
+    fn try_copy(&mut self, c: &()) -> Result<(), TryReserveError> {
+        Region::try_copy(self, c)
+    }
+
     fn idx(&self, i: usize) -> Self::ReadItem<'_> {
         *Region::idx(self, i)
     }
@@ -44,46 +133,155 @@ impl ColumnarBuf<u64> for Region<u64> {
 
 mod string {
     use crate::region::Region;
-    use crate::{Columnar, ColumnarBuf};
+    use crate::{Columnar, ColumnarBuf, TryReserveError};
 
+    #[cfg(feature = "allocator_api")]
+    use std::alloc::{Allocator, Global};
+
+    #[cfg(not(feature = "allocator_api"))]
     pub struct StringBuf {
         idx: Vec<usize>,
         data: Region<u8>,
     }
 
+    /// `StringBuf`'s inner `data` region can be generalized over a custom
+    /// [`std::alloc::Allocator`] with the `allocator_api` feature, just like
+    /// [`Region`] itself. Since [`Columnar::Buf`] names a single concrete
+    /// type, `ColumnarBuf<String>` is only ever implemented for the
+    /// `Global`-backed `StringBuf`; a custom-allocator `StringBuf<A>` is
+    /// reached directly via [`StringBuf::with_capacity_in`] instead.
+    #[cfg(feature = "allocator_api")]
+    pub struct StringBuf<A: Allocator + Clone = Global> {
+        idx: Vec<usize>,
+        data: Region<u8, A>,
+    }
+
     impl Columnar for String {
         type Buf = StringBuf;
+
+        fn from_read_item(item: &str) -> Self {
+            item.to_string()
+        }
     }
 
-    impl ColumnarBuf<String> for StringBuf {
-        type ReadItem<'a> = &'a str;
+    /// Generates the core logic shared by every `StringBuf<A>`, as inherent
+    /// methods. `ColumnarBuf<String>` (below) just forwards to these.
+    macro_rules! string_buf_methods {
+        () => {
+            pub fn copy(&mut self, c: &str) {
+                self.data.copy_slice(c.as_bytes());
+                self.idx.push(self.data.len());
+            }
 
-        fn copy(&mut self, c: &str) {
-            self.data.copy_slice(c.as_bytes());
-            self.idx.push(self.data.len());
-        }
+            /// Like [`StringBuf::copy`], but surfaces allocation failure as a
+            /// [`TryReserveError`] instead of aborting.
+            pub fn try_copy(&mut self, c: &str) -> Result<(), TryReserveError> {
+                // Reserve the index slot *before* writing into `data`, so that
+                // a failure here leaves the buffer unchanged instead of
+                // stranding orphaned bytes with no matching `idx` boundary.
+                self.idx.try_reserve(1)?;
+                self.data.try_copy_slice(c.as_bytes())?;
+                self.idx.push(self.data.len());
+                Ok(())
+            }
 
-        fn idx(&self, i: usize) -> Self::ReadItem<'_> {
-            let start = if i == 0 { 0 } else { self.idx[i - 1] };
-            let end = self.idx[i];
-            unsafe { std::str::from_utf8_unchecked(&self.data.slice(start, end)) }
+            pub fn idx(&self, i: usize) -> &str {
+                let start = if i == 0 { 0 } else { self.idx[i - 1] };
+                let end = self.idx[i];
+                unsafe { std::str::from_utf8_unchecked(self.data.slice(start, end)) }
+            }
+
+            pub fn len(&self) -> usize {
+                self.idx.len()
+            }
+        };
+    }
+
+    #[cfg(not(feature = "allocator_api"))]
+    impl StringBuf {
+        pub fn with_capacity(s: usize) -> Self {
+            StringBuf {
+                idx: Vec::with_capacity(s),
+                data: Region::with_limit_and_capacity(1_000_000 * 16, s),
+            }
         }
 
-        fn len(&self) -> usize {
-            self.idx.len()
+        string_buf_methods!();
+    }
+
+    #[cfg(feature = "allocator_api")]
+    impl StringBuf<Global> {
+        pub fn with_capacity(s: usize) -> Self {
+            Self::with_capacity_in(s, Global)
         }
+    }
 
-        fn with_capacity(s: usize) -> Self {
-            let idx = Vec::with_capacity(s);
-            let data = Region::with_limit_and_capacity(1_000_000 * 16, s);
-            StringBuf { idx, data }
+    #[cfg(feature = "allocator_api")]
+    impl<A: Allocator + Clone> StringBuf<A> {
+        /// Like [`StringBuf::with_capacity`], but backs the inner `data`
+        /// region with `alloc` instead of [`Global`] — e.g. a huge
+        /// pre-reserved arena. Reached directly on `StringBuf<A>` rather
+        /// than through [`ColumnarBuf::with_capacity`], since
+        /// [`Columnar::Buf`] names a single concrete type.
+        pub fn with_capacity_in(s: usize, alloc: A) -> Self {
+            StringBuf {
+                idx: Vec::with_capacity(s),
+                data: Region::with_limit_and_capacity_in(1_000_000 * 16, s, alloc),
+            }
         }
+
+        string_buf_methods!();
     }
+
+    /// Implements `ColumnarBuf<String>` for `$Self` by forwarding to the
+    /// inherent methods generated by `string_buf_methods!`.
+    macro_rules! impl_string_buf_columnar_buf {
+        ($Self:ty) => {
+            impl ColumnarBuf<String> for $Self {
+                type ReadItem<'a> = &'a str;
+
+                fn copy(&mut self, c: &str) {
+                    <$Self>::copy(self, c)
+                }
+
+                fn try_copy(&mut self, c: &str) -> Result<(), TryReserveError> {
+                    <$Self>::try_copy(self, c)
+                }
+
+                fn idx(&self, i: usize) -> Self::ReadItem<'_> {
+                    <$Self>::idx(self, i)
+                }
+
+                fn len(&self) -> usize {
+                    <$Self>::len(self)
+                }
+
+                fn with_capacity(s: usize) -> Self {
+                    <$Self>::with_capacity(s)
+                }
+            }
+        };
+    }
+
+    #[cfg(not(feature = "allocator_api"))]
+    impl_string_buf_columnar_buf!(StringBuf);
+
+    #[cfg(feature = "allocator_api")]
+    impl_string_buf_columnar_buf!(StringBuf<Global>);
 }
 
 mod vector {
-    use crate::{Columnar, ColumnarBuf};
-
+    use crate::{Columnar, ColumnarBuf, TryReserveError};
+
+    /// Unlike [`StringBuf`](crate::string::StringBuf), `VecBuf` cannot offer
+    /// a `with_capacity_in`-style allocator hook: `buf`'s type is `T::Buf`,
+    /// and [`Columnar::Buf`] names a single concrete type for any given `T`
+    /// (fixed to the `Global` allocator under the `allocator_api` feature).
+    /// There is no generic "allocator-aware `T::Buf`" to construct here.
+    /// Callers who need a `Vec<T>`'s elements backed by a custom allocator
+    /// should build the element buffer (a [`Region`](crate::region::Region)
+    /// or [`StringBuf`](crate::string::StringBuf)) directly with its own
+    /// `_in` constructor instead of going through this type.
     pub struct VecBuf<T: Columnar> {
         idx: Vec<usize>,
         buf: T::Buf,
@@ -91,6 +289,10 @@ mod vector {
 
     impl<T: Columnar> Columnar for Vec<T> {
         type Buf = VecBuf<T>;
+
+        fn from_read_item(item: IdxIter<'_, T>) -> Self {
+            item.map(T::from_read_item).collect()
+        }
     }
 
     impl<T: Columnar> ColumnarBuf<Vec<T>> for VecBuf<T> {
@@ -104,6 +306,19 @@ mod vector {
             self.idx.push(len);
         }
 
+        fn try_copy(&mut self, c: &[T]) -> Result<(), TryReserveError> {
+            // Reserve the index slot *before* writing into `buf`, so that a
+            // failure here leaves the buffer unchanged instead of stranding
+            // orphaned elements with no matching `idx` boundary.
+            self.idx.try_reserve(1)?;
+            for e in c {
+                self.buf.try_copy(e.borrow())?;
+            }
+            let len = self.buf.len();
+            self.idx.push(len);
+            Ok(())
+        }
+
         fn idx(&self, i: usize) -> IdxIter<'_, T> {
             let start = if i == 0 { 0 } else { self.idx[i - 1] };
             let end = self.idx[i];
@@ -119,9 +334,10 @@ mod vector {
         }
 
         fn with_capacity(s: usize) -> Self {
-            let idx = Vec::with_capacity(s);
-            let buf = T::Buf::with_capacity(s * 8);
-            VecBuf { idx, buf }
+            VecBuf {
+                idx: Vec::with_capacity(s),
+                buf: T::Buf::with_capacity(s * 8),
+            }
         }
     }
 
@@ -176,4 +392,110 @@ mod tests {
         assert_eq!(string_buf.idx(1), "xx");
         assert_eq!(string_buf.idx(2), "xx2");
     }
+
+    #[test]
+    fn test_string_buf_try_copy() {
+        let mut string_buf = StringBuf::with_capacity(1);
+        string_buf.try_copy("abc").unwrap();
+        string_buf.try_copy("xx").unwrap();
+
+        assert_eq!(string_buf.len(), 2);
+        assert_eq!(string_buf.idx(0), "abc");
+        assert_eq!(string_buf.idx(1), "xx");
+    }
+
+    #[test]
+    fn test_string_buf_into_values() {
+        let mut string_buf = StringBuf::with_capacity(1);
+        string_buf.copy("abc");
+        string_buf.copy("xx");
+
+        let values: Vec<String> = string_buf.into_values().collect();
+        assert_eq!(values, vec!["abc".to_string(), "xx".to_string()]);
+    }
+}
+
+#[cfg(test)]
+mod derive_tests {
+    use crate::{Columnar, ColumnarBuf};
+
+    #[derive(Columnar, Debug, PartialEq)]
+    struct Point {
+        x: u64,
+        y: u64,
+    }
+
+    #[test]
+    fn test_derive_struct() {
+        let mut buf = <Point as Columnar>::Buf::with_capacity(2);
+        buf.copy(&Point { x: 1, y: 2 });
+        buf.copy(&Point { x: 3, y: 4 });
+
+        assert_eq!(buf.len(), 2);
+        let first = buf.idx(0);
+        assert_eq!((first.x, first.y), (1, 2));
+
+        let values: Vec<Point> = buf.into_values().collect();
+        assert_eq!(values, vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]);
+    }
+
+    #[test]
+    fn test_derive_struct_try_copy() {
+        let mut buf = <Point as Columnar>::Buf::with_capacity(2);
+        buf.try_copy(&Point { x: 1, y: 2 }).unwrap();
+        buf.try_copy(&Point { x: 3, y: 4 }).unwrap();
+
+        assert_eq!(buf.len(), 2);
+        let values: Vec<Point> = buf.into_values().collect();
+        assert_eq!(values, vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]);
+    }
+
+    #[derive(Columnar, Debug, PartialEq)]
+    enum Shape {
+        Unit,
+        Sized(u64),
+    }
+
+    #[test]
+    fn test_derive_enum() {
+        let mut buf = <Shape as Columnar>::Buf::with_capacity(2);
+        buf.copy(&Shape::Unit);
+        buf.copy(&Shape::Sized(7));
+
+        assert_eq!(buf.len(), 2);
+        let values: Vec<Shape> = buf.into_values().collect();
+        assert_eq!(values, vec![Shape::Unit, Shape::Sized(7)]);
+    }
+
+    #[test]
+    fn test_derive_enum_try_copy() {
+        let mut buf = <Shape as Columnar>::Buf::with_capacity(2);
+        buf.try_copy(&Shape::Unit).unwrap();
+        buf.try_copy(&Shape::Sized(7)).unwrap();
+
+        assert_eq!(buf.len(), 2);
+        let values: Vec<Shape> = buf.into_values().collect();
+        assert_eq!(values, vec![Shape::Unit, Shape::Sized(7)]);
+    }
+}
+
+#[cfg(all(test, feature = "allocator_api"))]
+mod string_allocator_tests {
+    use std::alloc::Global;
+
+    use crate::string::StringBuf;
+
+    #[test]
+    fn test_string_buf_with_capacity_in() {
+        // `Global` here stands in for any custom allocator; the point is
+        // that `StringBuf<A>` is reachable and usable without going through
+        // `ColumnarBuf::with_capacity`.
+        let mut string_buf = StringBuf::with_capacity_in(2, Global);
+        string_buf.copy("abc");
+        string_buf.copy("xx");
+
+        assert_eq!(string_buf.len(), 2);
+        assert_eq!(string_buf.idx(0), "abc");
+        assert_eq!(string_buf.idx(1), "xx");
+    }
 }
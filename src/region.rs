@@ -1,30 +1,294 @@
 // adapted from https://github.com/frankmcsherry/columnation/blob/master/src/lib.rs
 
+use std::collections::TryReserveError;
+
+/// The rule [`Region::reserve`] uses to size a fresh allocation once the
+/// current `local` buffer runs out of room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Growth {
+    /// Double the previous allocation size, as `Vec` itself does. The
+    /// default, and a good fit for workloads with no particular memory
+    /// shape in mind.
+    #[default]
+    PowerOfTwo,
+    /// Round the new allocation size up to a multiple of the given page
+    /// size, for callers that want allocations to line up with OS or
+    /// arena page boundaries.
+    PageAligned(usize),
+    /// Always allocate exactly this many items, capped at the region's
+    /// limit (or `count`, if larger), for callers that know their
+    /// workload's natural chunk size.
+    Fixed(usize),
+}
+
+impl Growth {
+    /// Computes the size of the next allocation, given the current `local`
+    /// capacity, the number of items that must fit, and the region's limit.
+    fn next_len(&self, capacity: usize, count: usize, limit: usize) -> usize {
+        let mut next_len = match *self {
+            Growth::PowerOfTwo => (capacity + 1).next_power_of_two(),
+            Growth::PageAligned(page) => {
+                let page = page.max(1);
+                (capacity + count).div_ceil(page) * page
+            }
+            Growth::Fixed(n) => n,
+        };
+        next_len = std::cmp::min(next_len, limit);
+        next_len = std::cmp::max(count, next_len);
+        next_len
+    }
+}
+
+/// Generates the bulk of `Region`'s methods, which are identical between the
+/// stable (global-allocator-only) and `allocator_api` variants of the type
+/// modulo the inner `Vec` type and how a fresh one is constructed. Keeping
+/// this as a single macro body, invoked once per variant below, means a
+/// bugfix only has to be written once instead of twice.
+macro_rules! region_methods {
+    ($Vec:ty) => {
+        pub fn idx(&self, i: usize) -> &T {
+            let mut l = 0;
+            for s in &self.stash {
+                if s.len() + l > i {
+                    return &s[i - l];
+                }
+                l += s.len();
+            }
+            &self.local[i - l]
+        }
+
+        pub fn slice(&self, start: usize, end: usize) -> &[T] {
+            let mut l = 0;
+            for s in &self.stash {
+                if s.len() + l > start {
+                    let start = start - l;
+                    let end = end - l;
+                    return &s[start..end];
+                }
+                l += s.len();
+            }
+            let start = start - l;
+            let end = end - l;
+            &self.local[start..end]
+        }
+
+        /// Clears the contents without dropping any elements, releasing all
+        /// stashed and recycled capacity. Use [`Region::recycle`] to keep that
+        /// capacity around for the next fill instead.
+        #[inline]
+        pub fn clear(&mut self) {
+            self.local.clear();
+            self.stash.clear();
+            self.free.clear();
+        }
+
+        /// Like [`Region::clear`], but moves the (now-empty) stashed allocations
+        /// onto an internal free-list instead of dropping them, so the next
+        /// [`Region::reserve`] can reuse one instead of allocating from scratch.
+        /// The active `local` buffer is cleared in place and kept as-is.
+        pub fn recycle(&mut self) {
+            self.local.clear();
+            for mut stashed in self.stash.drain(..) {
+                stashed.clear();
+                self.free.push(stashed);
+            }
+        }
+
+        pub fn copy(&mut self, t: &T) {
+            self.reserve(1);
+            self.local.push(*t);
+        }
+
+        /// Like [`Region::copy`], but surfaces allocation failure as a
+        /// [`TryReserveError`] instead of panicking.
+        pub fn try_copy(&mut self, t: &T) -> Result<(), TryReserveError> {
+            self.try_reserve(1)?;
+            self.local.push(*t);
+            Ok(())
+        }
+
+        /// Copies a slice of cloneable items into the region.
+        #[inline]
+        pub fn copy_slice(&mut self, items: &[T]) {
+            self.reserve(items.len());
+            self.local.extend_from_slice(items);
+        }
+
+        /// Like [`Region::copy_slice`], but surfaces allocation failure as a
+        /// [`TryReserveError`] instead of panicking.
+        #[inline]
+        pub fn try_copy_slice(&mut self, items: &[T]) -> Result<(), TryReserveError> {
+            self.try_reserve(items.len())?;
+            self.local.extend_from_slice(items);
+            Ok(())
+        }
+
+        /// Ensures that there is space in `self.local` to copy at least `count` items.
+        ///
+        /// Panics on allocation failure. Callers that need to
+        /// recover from OOM should use [`Region::try_reserve`] instead.
+        #[inline(always)]
+        pub fn reserve(&mut self, count: usize) {
+            self.try_reserve(count)
+                .unwrap_or_else(|e| panic!("Region allocation of {count} items failed: {e}"));
+        }
+
+        /// Like [`Region::reserve`], but returns a [`TryReserveError`] instead of
+        /// panicking when the underlying allocation fails. The region is left
+        /// unchanged on failure.
+        #[inline(always)]
+        pub fn try_reserve(&mut self, count: usize) -> Result<(), TryReserveError> {
+            // Check if `item` fits into `self.local` without reallocation.
+            // If not, stash `self.local` and increase the allocation.
+            if count > self.local.capacity() - self.local.len() {
+                let next_len = self
+                    .growth
+                    .next_len(self.local.capacity(), count, self.limit);
+                // Prefer a recycled allocation (from `recycle`) over allocating
+                // fresh. `take_recycled` only hands out one that already holds
+                // `next_len` items, and if the resize below fails, any taken
+                // allocation with capacity goes back on the free-list, so a
+                // failed grow doesn't silently shrink the free-list.
+                let mut new_local = match self.take_recycled(next_len) {
+                    Some(recycled) => recycled,
+                    None => self.new_vec(),
+                };
+                if new_local.capacity() < next_len {
+                    if let Err(e) = new_local.try_reserve_exact(next_len - new_local.len()) {
+                        if new_local.capacity() > 0 {
+                            self.free.push(new_local);
+                        }
+                        return Err(e);
+                    }
+                }
+                let old_local = std::mem::replace(&mut self.local, new_local);
+                if old_local.is_empty() {
+                    // Nothing is reading through this allocation any more
+                    // (e.g. it was just `recycle()`d), so it is just as
+                    // reusable as a stash entry would be; recycle it too
+                    // instead of dropping its capacity on the floor.
+                    if old_local.capacity() > 0 {
+                        self.free.push(old_local);
+                    }
+                } else {
+                    self.stash.push(old_local);
+                }
+            }
+            Ok(())
+        }
+
+        /// Removes and returns the first free-list allocation with capacity at
+        /// least `min_capacity`, if any.
+        fn take_recycled(&mut self, min_capacity: usize) -> Option<$Vec> {
+            let pos = self.free.iter().position(|v| v.capacity() >= min_capacity)?;
+            Some(self.free.swap_remove(pos))
+        }
+
+        /// Collapses `local`, `stash`, and any free-list allocations down to the
+        /// minimal set of allocations needed to hold the current contents,
+        /// releasing spare capacity back to the allocator.
+        ///
+        /// This may move previously-inserted items into a fresh allocation, so,
+        /// as after [`Region::clear`], any addresses obtained from [`Region::idx`]
+        /// or [`Region::slice`] before the call should be treated as invalidated.
+        pub fn shrink_to_fit(&mut self) {
+            self.free.clear();
+            self.free.shrink_to_fit();
+            if self.stash.is_empty() {
+                self.local.shrink_to_fit();
+                self.stash.shrink_to_fit();
+                return;
+            }
+            let len = self.len();
+            let mut merged = self.new_vec();
+            merged
+                .try_reserve_exact(len)
+                .expect("shrink_to_fit: allocation failed");
+            for stashed in self.stash.drain(..) {
+                merged.extend_from_slice(&stashed);
+            }
+            merged.extend_from_slice(&self.local);
+            self.local = merged;
+            self.stash.shrink_to_fit();
+        }
+
+        /// The number of items current held in the region.
+        pub fn len(&self) -> usize {
+            self.local.len() + self.stash.iter().map(|r| r.len()).sum::<usize>()
+        }
+
+        /// Returns `true` if the region holds no items.
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        #[inline]
+        pub fn heap_size(&self, mut callback: impl FnMut(usize, usize)) {
+            // Calculate heap size for local, stash, and stash entries
+            let size_of_t = std::mem::size_of::<T>();
+            callback(
+                self.local.len() * size_of_t,
+                self.local.capacity() * size_of_t,
+            );
+            callback(
+                self.stash.len() * std::mem::size_of::<$Vec>(),
+                self.stash.capacity() * std::mem::size_of::<$Vec>(),
+            );
+            for stash in &self.stash {
+                callback(stash.len() * size_of_t, stash.capacity() * size_of_t);
+            }
+            callback(
+                self.free.len() * std::mem::size_of::<$Vec>(),
+                self.free.capacity() * std::mem::size_of::<$Vec>(),
+            );
+            for free in &self.free {
+                callback(0, free.capacity() * size_of_t);
+            }
+        }
+    };
+}
+
 /// A region allocator which holds items at stable memory locations.
 ///
 /// Items once inserted will not be moved, and their locations in memory
-/// can be relied on by others, until the region is cleared.
+/// can be relied on by others, until the region is cleared (or its
+/// capacity is otherwise collapsed, e.g. via [`Region::shrink_to_fit`]).
 ///
 /// This type accepts owned data, rather than references, and does not
 /// itself intend to implement `Region`. Rather, it is a useful building
 /// block for other less-safe code that wants allocated data to remain at
 /// fixed memory locations.
+///
+/// `Region` can be generalized over a custom [`std::alloc::Allocator`] by
+/// building with the (nightly-only) `allocator_api` feature; see
+/// [`Region::with_limit_in`]/[`Region::with_limit_and_capacity_in`]. Without
+/// that feature, `Region` is always backed by the global allocator, and
+/// builds on stable Rust.
+#[cfg(not(feature = "allocator_api"))]
 pub struct Region<T: Copy> {
     /// The active allocation into which we are writing.
     local: Vec<T>,
     /// All previously active allocations.
     stash: Vec<Vec<T>>,
+    /// Retired allocations available for reuse by `reserve`, populated by
+    /// [`Region::recycle`].
+    free: Vec<Vec<T>>,
     /// The maximum allocation size
     limit: usize,
+    /// The rule used to size the next allocation in `reserve`.
+    growth: Growth,
 }
 
+#[cfg(not(feature = "allocator_api"))]
 impl<T: Copy> Region<T> {
     /// Construct a [Region] with a allocation size limit.
     pub fn with_limit(limit: usize) -> Self {
         Self {
-            local: Default::default(),
-            stash: Default::default(),
+            local: Vec::new(),
+            stash: Vec::new(),
+            free: Vec::new(),
             limit,
+            growth: Growth::default(),
         }
     }
 
@@ -35,92 +299,262 @@ impl<T: Copy> Region<T> {
         region
     }
 
-    pub fn idx(&self, i: usize) -> &T {
-        let mut l = 0;
-        for s in &self.stash {
-            if s.len() + l > i {
-                return &s[i - l];
-            }
-            l += s.len();
+    /// Sets the policy used to size future allocations in [`Region::reserve`].
+    pub fn set_growth(&mut self, growth: Growth) {
+        self.growth = growth;
+    }
+
+    /// Constructs a fresh, empty backing allocation.
+    fn new_vec(&self) -> Vec<T> {
+        Vec::new()
+    }
+
+    region_methods!(Vec<T>);
+}
+
+#[cfg(feature = "allocator_api")]
+use std::alloc::{Allocator, Global};
+
+#[cfg(feature = "allocator_api")]
+pub struct Region<T: Copy, A: Allocator + Clone = Global> {
+    /// The active allocation into which we are writing.
+    local: Vec<T, A>,
+    /// All previously active allocations.
+    stash: Vec<Vec<T, A>>,
+    /// Retired allocations available for reuse by `reserve`, populated by
+    /// [`Region::recycle`].
+    free: Vec<Vec<T, A>>,
+    /// The maximum allocation size
+    limit: usize,
+    /// The rule used to size the next allocation in `reserve`.
+    growth: Growth,
+    /// The allocator used for `local` and every entry in `stash`/`free`.
+    alloc: A,
+}
+
+#[cfg(feature = "allocator_api")]
+impl<T: Copy> Region<T, Global> {
+    /// Construct a [Region] with a allocation size limit.
+    pub fn with_limit(limit: usize) -> Self {
+        Self::with_limit_in(limit, Global)
+    }
+
+    /// Allocates a new `Self` that can accept `count` items without reallocation.
+    pub fn with_limit_and_capacity(limit: usize, count: usize) -> Self {
+        Self::with_limit_and_capacity_in(limit, count, Global)
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl<T: Copy, A: Allocator + Clone> Region<T, A> {
+    /// Like [`Region::with_limit`], but backed by the given `alloc` instead of [`Global`].
+    pub fn with_limit_in(limit: usize, alloc: A) -> Self {
+        Self {
+            local: Vec::new_in(alloc.clone()),
+            stash: Vec::new(),
+            free: Vec::new(),
+            limit,
+            growth: Growth::default(),
+            alloc,
+        }
+    }
+
+    /// Sets the policy used to size future allocations in [`Region::reserve`].
+    pub fn set_growth(&mut self, growth: Growth) {
+        self.growth = growth;
+    }
+
+    /// Like [`Region::with_limit_and_capacity`], but backed by the given `alloc`
+    /// instead of [`Global`].
+    pub fn with_limit_and_capacity_in(limit: usize, count: usize, alloc: A) -> Self {
+        let mut region = Self::with_limit_in(limit, alloc);
+        region.reserve(count);
+        region
+    }
+
+    /// Constructs a fresh, empty backing allocation using this region's allocator.
+    fn new_vec(&self) -> Vec<T, A> {
+        Vec::new_in(self.alloc.clone())
+    }
+
+    region_methods!(Vec<T, A>);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Growth, Region};
+
+    #[test]
+    fn test_growth_page_aligned() {
+        let mut region: Region<u64> = Region::with_limit(1_000_000);
+        region.set_growth(Growth::PageAligned(8));
+        region.reserve(3);
+        // Filling past the page boundary forces a second allocation.
+        for i in 0..9 {
+            region.copy(&i);
+        }
+        assert_eq!(region.len(), 9);
+        for i in 0..9 {
+            assert_eq!(*region.idx(i as usize), i);
+        }
+    }
+
+    #[test]
+    fn test_recycle_reuses_freed_allocation() {
+        // Fixed growth means every allocation `reserve` makes is the same
+        // size, so a chunk recycled via `recycle()` is eligible to satisfy a
+        // later grow once `local`'s own (already-full) capacity runs out.
+        let mut region: Region<u64> = Region::with_limit(1_000_000);
+        region.set_growth(Growth::Fixed(4));
+        region.reserve(4);
+        for i in 0..4 {
+            region.copy(&i);
+        }
+        // Forces a new `local` allocation, stashing the first (full) one.
+        region.copy(&4);
+        assert_eq!(region.stash.len(), 1);
+
+        region.recycle();
+        assert_eq!(region.len(), 0);
+        assert_eq!(
+            region.free.len(),
+            1,
+            "the stashed allocation should be recycled"
+        );
+        assert_eq!(region.stash.len(), 0);
+
+        // Refill `local` up to its own retained capacity; this alone must
+        // not touch the free-list.
+        for i in 0..4 {
+            region.copy(&i);
+        }
+        assert_eq!(
+            region.free.len(),
+            1,
+            "filling local's own capacity shouldn't touch the free-list"
+        );
+
+        // One more item forces growth past `local`'s own capacity, which
+        // should now be satisfied by the recycled free-list entry instead of
+        // a fresh allocation.
+        region.copy(&99);
+        assert_eq!(
+            region.free.len(),
+            0,
+            "growth should have consumed the recycled allocation"
+        );
+        assert_eq!(region.stash.len(), 1);
+
+        assert_eq!(region.len(), 5);
+        assert_eq!(*region.idx(0), 0);
+        assert_eq!(*region.idx(3), 3);
+        assert_eq!(*region.idx(4), 99);
+    }
+
+    #[test]
+    fn test_try_reserve_recycles_emptied_local() {
+        // `recycle()` clears `local` in place without stashing it. A later
+        // grow past that (now-empty) `local`'s own capacity must push it
+        // onto the free-list too, not just stash entries, or its capacity
+        // is silently dropped instead of being reused.
+        let mut region: Region<u64> = Region::with_limit(1_000_000);
+        region.reserve(4);
+        region.recycle();
+        assert!(region.free.is_empty());
+        assert!(region.stash.is_empty());
+
+        region.reserve(8);
+        assert_eq!(
+            region.free.len(),
+            1,
+            "the emptied local buffer should have been recycled instead of dropped"
+        );
+    }
+
+    #[test]
+    fn test_shrink_to_fit_collapses_allocations() {
+        let mut region: Region<u64> = Region::with_limit_and_capacity(1_000_000, 1);
+        for i in 0..5 {
+            region.copy(&i);
+        }
+        region.shrink_to_fit();
+        assert_eq!(region.len(), 5);
+        for i in 0..5 {
+            assert_eq!(*region.idx(i as usize), i);
         }
-        &self.local[i - l]
+    }
+}
+
+#[cfg(all(test, feature = "allocator_api"))]
+mod allocator_tests {
+    use std::alloc::{AllocError, Allocator, Global, Layout};
+    use std::cell::Cell;
+    use std::ptr::NonNull;
+    use std::rc::Rc;
+
+    use super::Region;
+
+    /// A test allocator that fails every allocation past the first `n`,
+    /// so tests can deterministically exercise `try_reserve`'s failure path.
+    #[derive(Clone)]
+    struct FailAfter {
+        remaining: Rc<Cell<usize>>,
     }
 
-    pub fn slice(&self, start: usize, end: usize) -> &[T] {
-        let mut l = 0;
-        for s in &self.stash {
-            if s.len() + l > start {
-                let start = start - l;
-                let end = end - l;
-                return &s[start..end];
+    impl FailAfter {
+        fn new(n: usize) -> Self {
+            FailAfter {
+                remaining: Rc::new(Cell::new(n)),
             }
-            l += s.len();
-        }
-        let start = start - l;
-        let end = end - l;
-        &self.local[start..end]
-    }
-
-    /// Clears the contents without dropping any elements.
-    #[inline]
-    pub fn clear(&mut self) {
-        self.local.clear();
-        self.stash.clear();
-    }
-
-    pub fn copy(&mut self, t: &T) {
-        self.reserve(1);
-        self.local.push(*t);
-    }
-
-    /// Copies a slice of cloneable items into the region.
-    #[inline]
-    pub fn copy_slice(&mut self, items: &[T]) {
-        self.reserve(items.len());
-        self.local.extend_from_slice(items);
-    }
-
-    /// Ensures that there is space in `self.local` to copy at least `count` items.
-    #[inline(always)]
-    pub fn reserve(&mut self, count: usize) {
-        // Check if `item` fits into `self.local` without reallocation.
-        // If not, stash `self.local` and increase the allocation.
-        if count > self.local.capacity() - self.local.len() {
-            // Increase allocated capacity in powers of two.
-            // We could choose a different rule here if we wanted to be
-            // more conservative with memory (e.g. page size allocations).
-            let mut next_len = (self.local.capacity() + 1).next_power_of_two();
-            next_len = std::cmp::min(next_len, self.limit);
-            next_len = std::cmp::max(count, next_len);
-            let new_local = Vec::with_capacity(next_len);
-            if self.local.is_empty() {
-                self.local = new_local;
-            } else {
-                self.stash
-                    .push(std::mem::replace(&mut self.local, new_local));
+        }
+    }
+
+    unsafe impl Allocator for FailAfter {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            let remaining = self.remaining.get();
+            if remaining == 0 {
+                return Err(AllocError);
             }
+            self.remaining.set(remaining - 1);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            unsafe { Global.deallocate(ptr, layout) }
         }
     }
 
-    /// The number of items current held in the region.
-    pub fn len(&self) -> usize {
-        self.local.len() + self.stash.iter().map(|r| r.len()).sum::<usize>()
+    #[test]
+    fn test_custom_allocator_round_trip() {
+        let alloc = FailAfter::new(8);
+        let mut region: Region<u64, FailAfter> =
+            Region::with_limit_and_capacity_in(1_000_000, 4, alloc);
+        for i in 0..4 {
+            region.copy(&i);
+        }
+        assert_eq!(region.len(), 4);
+        assert_eq!(*region.idx(0), 0);
+        assert_eq!(*region.idx(3), 3);
     }
 
-    #[inline]
-    pub fn heap_size(&self, mut callback: impl FnMut(usize, usize)) {
-        // Calculate heap size for local, stash, and stash entries
-        let size_of_t = std::mem::size_of::<T>();
-        callback(
-            self.local.len() * size_of_t,
-            self.local.capacity() * size_of_t,
-        );
-        callback(
-            self.stash.len() * std::mem::size_of::<Vec<T>>(),
-            self.stash.capacity() * std::mem::size_of::<Vec<T>>(),
-        );
-        for stash in &self.stash {
-            callback(stash.len() * size_of_t, stash.capacity() * size_of_t);
+    #[test]
+    fn test_try_reserve_failure_leaves_region_unchanged() {
+        let alloc = FailAfter::new(1);
+        let mut region: Region<u64, FailAfter> =
+            Region::with_limit_and_capacity_in(1_000_000, 2, alloc);
+        for i in 0..2 {
+            region.copy(&i);
         }
+        assert_eq!(region.len(), 2);
+
+        // The allocator's one allowance was spent on `with_limit_and_capacity_in`,
+        // so growing past the current capacity must fail...
+        let result = region.try_copy(&99);
+        assert!(result.is_err());
+
+        // ...and the region must be left exactly as it was before the call.
+        assert_eq!(region.len(), 2);
+        assert_eq!(*region.idx(0), 0);
+        assert_eq!(*region.idx(1), 1);
     }
 }